@@ -1,12 +1,16 @@
+use std::fmt;
 use std::fs;
 use std::io;
 use structopt::StructOpt;
 
 extern crate sqlparser;
-use sqlparser::ast::{Statement, Query, SetExpr, Function, ObjectName, Expr, SelectItem, Select, TableWithJoins, Values, Cte};
+use sqlparser::ast::{Statement, Query, SetExpr, SetOperator, Function, ObjectName, Expr, SelectItem, Select, TableWithJoins, TableFactor, TableAlias, Values, Cte, UnaryOperator, BinaryOperator};
 use sqlparser::dialect::GenericDialect;
 use sqlparser::parser::Parser;
 
+extern crate postgres;
+use postgres::{Client, NoTls};
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "picaresql", about = "Debug your SQL")]
 pub struct Config {
@@ -15,6 +19,15 @@ pub struct Config {
 
     #[structopt(name = "sql file")]
     pub sql_file: String,
+
+    #[structopt(long, default_value = "1.0", help = "Highlight the first clause step whose row count drops by at least this fraction of the previous step's count (1.0 only highlights a drop to zero)")]
+    pub drop_ratio_threshold: f64,
+
+    #[structopt(long, help = "Run each clause step through EXPLAIN (ANALYZE, BUFFERS) instead of SELECT COUNT(*), to compare the planner's row estimates against what actually happened")]
+    pub explain: bool,
+
+    #[structopt(long, help = "Before counting anything, check that every referenced table (and resolvable GROUP BY/HAVING column) exists in information_schema")]
+    pub validate_schema: bool,
 }
 
 impl Config {
@@ -23,6 +36,35 @@ impl Config {
     }
 }
 
+#[derive(Debug)]
+pub enum PicaresqlError {
+    Io(io::Error),
+    Database(postgres::Error),
+    SchemaValidation(Vec<String>),
+}
+
+impl fmt::Display for PicaresqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PicaresqlError::Io(e) => write!(f, "{}", e),
+            PicaresqlError::Database(e) => write!(f, "{}", e),
+            PicaresqlError::SchemaValidation(problems) => write!(f, "schema validation failed:\n{}", problems.iter().map(|p| format!("  - {}", p)).collect::<Vec<_>>().join("\n")),
+        }
+    }
+}
+
+impl From<io::Error> for PicaresqlError {
+    fn from(err: io::Error) -> Self {
+        PicaresqlError::Io(err)
+    }
+}
+
+impl From<postgres::Error> for PicaresqlError {
+    fn from(err: postgres::Error) -> Self {
+        PicaresqlError::Database(err)
+    }
+}
+
 #[derive(Debug)]
 struct Analysis {
     pub query_analyses: Vec<QueryAnalysis>,
@@ -60,6 +102,9 @@ impl Analysis {
 struct QueryAnalysis {
     pub query: String,
     pub clause_steps: Vec<String>,
+    pub explain_steps: Vec<String>,
+    pub referenced_tables: Vec<String>,
+    pub referenced_columns: Vec<ColumnReference>,
 }
 
 #[derive(Debug)]
@@ -67,13 +112,165 @@ struct InsertAnalysis {
     pub insert_statement: String,
     pub target_table_initial_count: String,
     pub payload_count: String,
+    pub referenced_tables: Vec<String>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct ColumnReference {
+    pub table: Option<String>,
+    pub column: String,
+}
 
-pub fn run(config: Config) {
-    let sql = config.sql().unwrap();
+
+pub fn run(config: Config) -> Result<(), PicaresqlError> {
+    let sql = config.sql()?;
     let analysis = analyze(&sql);
-    println!("{:?}", analysis);
+    let mut client = Client::connect(&config.connection_string, NoTls)?;
+
+    if config.validate_schema {
+        validate_schema(&mut client, &analysis)?;
+    }
+
+    report(&mut client, &analysis, config.drop_ratio_threshold, config.explain)
+}
+
+fn validate_schema(client: &mut Client, analysis: &Analysis) -> Result<(), PicaresqlError> {
+    let mut problems = vec![];
+
+    let mut tables: Vec<&String> = analysis.query_analyses.iter().flat_map(|qa| &qa.referenced_tables)
+        .chain(analysis.insert_analyses.iter().flat_map(|ia| &ia.referenced_tables))
+        .collect();
+    tables.sort();
+    tables.dedup();
+
+    for table in tables {
+        if !table_exists(client, table)? {
+            problems.push(format!("relation \"{}\" does not exist", table));
+        }
+    }
+
+    for query_analysis in &analysis.query_analyses {
+        for column in &query_analysis.referenced_columns {
+            if let Some(table) = &column.table {
+                if !column_exists(client, table, &column.column)? {
+                    problems.push(format!("column \"{}\" not found on table \"{}\"", column.column, table));
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(PicaresqlError::SchemaValidation(problems))
+    }
+}
+
+fn table_exists(client: &mut Client, table: &str) -> Result<bool, PicaresqlError> {
+    let (schema, table_name) = split_qualified_name(table);
+    let row = client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM information_schema.tables WHERE table_name = $1 AND ($2::text IS NULL OR table_schema = $2))",
+        &[&table_name, &schema],
+    )?;
+    Ok(row.get(0))
+}
+
+fn column_exists(client: &mut Client, table: &str, column: &str) -> Result<bool, PicaresqlError> {
+    let (schema, table_name) = split_qualified_name(table);
+    let row = client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = $1 AND column_name = $2 AND ($3::text IS NULL OR table_schema = $3))",
+        &[&table_name, &column, &schema],
+    )?;
+    Ok(row.get(0))
+}
+
+// `referenced_tables`/resolved column qualifiers carry `ObjectName::to_string()`, which
+// dot-joins a schema-qualified name (e.g. "public.table_1"). information_schema.tables
+// and information_schema.columns key on the bare table name in one column and the
+// schema in another, so split the two apart before querying instead of comparing the
+// whole dotted string against `table_name`.
+fn split_qualified_name(name: &str) -> (Option<&str>, &str) {
+    match name.rsplit_once('.') {
+        Some((schema, table)) => (Some(schema), table),
+        None => (None, name),
+    }
+}
+
+fn report(client: &mut Client, analysis: &Analysis, drop_ratio_threshold: f64, explain: bool) -> Result<(), PicaresqlError> {
+    for query_analysis in &analysis.query_analyses {
+        report_query_analysis(client, query_analysis, drop_ratio_threshold, explain)?;
+    }
+    for insert_analysis in &analysis.insert_analyses {
+        report_insert_analysis(client, insert_analysis)?;
+    }
+    Ok(())
+}
+
+fn report_query_analysis(client: &mut Client, query_analysis: &QueryAnalysis, drop_ratio_threshold: f64, explain: bool) -> Result<(), PicaresqlError> {
+    println!("\n{}", query_analysis.query);
+
+    if explain {
+        return report_explain_steps(client, query_analysis);
+    }
+
+    let mut previous_count: Option<i64> = None;
+    let mut collapse_reported = false;
+    for step in &query_analysis.clause_steps {
+        let count = count_for(client, step)?;
+        let collapsed = previous_count
+            .map(|previous| drop_ratio(previous, count) >= drop_ratio_threshold)
+            .unwrap_or(false);
+
+        let marker = if collapsed && !collapse_reported { "  <-- row count collapsed here" } else { "" };
+        collapse_reported = collapse_reported || collapsed;
+
+        println!("  {:>10} | {}{}", count, step, marker);
+        previous_count = Some(count);
+    }
+
+    Ok(())
+}
+
+fn report_explain_steps(client: &mut Client, query_analysis: &QueryAnalysis) -> Result<(), PicaresqlError> {
+    for (step, explain_step) in query_analysis.clause_steps.iter().zip(&query_analysis.explain_steps) {
+        let plan = explain_text_for(client, explain_step)?;
+        println!("  {}\n{}", step, indent(&plan));
+    }
+    Ok(())
+}
+
+fn explain_text_for(client: &mut Client, sql: &str) -> Result<String, PicaresqlError> {
+    let rows = client.query(sql, &[])?;
+    let lines: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    Ok(lines.join("\n"))
+}
+
+fn indent(text: &str) -> String {
+    text.lines().map(|line| format!("    {}", line)).collect::<Vec<_>>().join("\n")
+}
+
+fn report_insert_analysis(client: &mut Client, insert_analysis: &InsertAnalysis) -> Result<(), PicaresqlError> {
+    let target_table_count = count_for(client, &insert_analysis.target_table_initial_count)?;
+    let payload_count = count_for(client, &insert_analysis.payload_count)?;
+
+    println!("\n{}", insert_analysis.insert_statement);
+    println!("  target table currently has {} rows", target_table_count);
+    println!("  payload would add {} rows", payload_count);
+
+    Ok(())
+}
+
+fn count_for(client: &mut Client, sql: &str) -> Result<i64, PicaresqlError> {
+    let row = client.query_one(sql, &[])?;
+    Ok(row.get(0))
+}
+
+fn drop_ratio(previous_count: i64, current_count: i64) -> f64 {
+    if previous_count == 0 {
+        0.0
+    } else {
+        (previous_count - current_count) as f64 / previous_count as f64
+    }
 }
 
 fn analyze(sql: &str) -> Analysis {
@@ -89,10 +286,14 @@ fn analyze_insert(table_name: &ObjectName, source: &Query, full_statement: &Stat
     let target_table_initial_count = format!("SELECT COUNT(*) FROM {}", table_name);
     let payload_count = get_payload_count_query(source);
 
+    let mut referenced_tables = vec![table_name.to_string()];
+    referenced_tables.extend(referenced_tables_in_query(source));
+
     InsertAnalysis {
         insert_statement: full_statement.to_string(),
         target_table_initial_count,
         payload_count,
+        referenced_tables,
     }
 }
 
@@ -100,26 +301,185 @@ fn get_payload_count_query(query: &Query) -> String {
     match &query.body {
         SetExpr::Select(select) => transform_select_projection_to_count(*select.clone()),
         SetExpr::Values(values) => get_values_count_query(values),
-        _ => panic!("What are you trying to INSERT if not a SELECT or VALUES?")
+        set_expr @ SetExpr::SetOperation { .. } => count_wrapped_set_expr(set_expr, &query.ctes),
+        _ => panic!("What are you trying to INSERT if not a SELECT, VALUES, or set operation?")
     }
 }
 
+fn count_wrapped_set_expr(set_expr: &SetExpr, ctes: &[Cte]) -> String {
+    let inner_query = Query {
+        ctes: vec![],
+        body: set_expr.clone(),
+        order_by: vec![],
+        limit: None,
+        offset: None,
+        fetch: None,
+    };
+
+    let outer_select = Select {
+        projection: create_count_star_projection(),
+        from: vec![TableWithJoins {
+            relation: TableFactor::Derived {
+                lateral: false,
+                subquery: Box::new(inner_query),
+                alias: Some(TableAlias { name: String::from("combined"), columns: vec![] }),
+            },
+            joins: vec![],
+        }],
+        selection: None,
+        group_by: vec![],
+        having: None,
+        distinct: false,
+    };
+
+    query_string_for(&outer_select, ctes)
+}
+
 fn transform_select_projection_to_count(mut select: Select) -> String {
     select.projection = create_count_star_projection();
     select.to_string()
 }
 
 fn get_values_count_query(values: &Values) -> String {
-    format!("SELECT {}", values.0.len())
+    // Cast to bigint so this lines up with the COUNT(*)/bigint results every other
+    // clause step produces -- a bare integer literal comes back as Postgres int4,
+    // which count_for's i64 column read rejects.
+    format!("SELECT {}::bigint", values.0.len())
 }
 
 fn analyze_query(query: &Query) -> QueryAnalysis {
+    let clause_steps = get_all_clause_steps(query);
+    let explain_steps = clause_steps.iter().map(|step| explain_wrap(step)).collect();
+
     QueryAnalysis {
         query: query.to_string(),
-        clause_steps: get_all_clause_steps(query),
+        clause_steps,
+        explain_steps,
+        referenced_tables: referenced_tables_in_query(query),
+        referenced_columns: referenced_columns_in_query(query),
     }
 }
 
+fn referenced_tables_in_query(query: &Query) -> Vec<String> {
+    let mut tables: Vec<String> = query.ctes.iter().flat_map(|cte| referenced_tables_in_query(&cte.query)).collect();
+    tables.extend(referenced_tables_in_set_expr(&query.body));
+
+    // CTE names are resolved against the WITH clause, not a real relation, so they
+    // shouldn't be flagged as a missing table during schema validation.
+    let cte_names: Vec<String> = query.ctes.iter().map(|cte| cte.alias.name.clone()).collect();
+    tables.retain(|table| !cte_names.contains(table));
+
+    tables
+}
+
+fn referenced_tables_in_set_expr(set_expr: &SetExpr) -> Vec<String> {
+    match set_expr {
+        SetExpr::Select(select) => select.from.iter().flat_map(referenced_tables_in_table_with_joins).collect(),
+        SetExpr::SetOperation { left, right, .. } => {
+            let mut tables = referenced_tables_in_set_expr(left);
+            tables.extend(referenced_tables_in_set_expr(right));
+            tables
+        }
+        _ => vec![],
+    }
+}
+
+fn referenced_tables_in_table_with_joins(table_with_joins: &TableWithJoins) -> Vec<String> {
+    let mut tables = referenced_tables_in_table_factor(&table_with_joins.relation);
+    for join in &table_with_joins.joins {
+        tables.extend(referenced_tables_in_table_factor(&join.relation));
+    }
+    tables
+}
+
+fn referenced_tables_in_table_factor(table_factor: &TableFactor) -> Vec<String> {
+    match table_factor {
+        TableFactor::Table { name, .. } => vec![name.to_string()],
+        TableFactor::Derived { subquery, .. } => referenced_tables_in_query(subquery),
+        TableFactor::NestedJoin(table_with_joins) => referenced_tables_in_table_with_joins(table_with_joins),
+    }
+}
+
+fn referenced_columns_in_query(query: &Query) -> Vec<ColumnReference> {
+    let mut columns: Vec<ColumnReference> = query.ctes.iter().flat_map(|cte| referenced_columns_in_query(&cte.query)).collect();
+
+    if let SetExpr::Select(select) = &query.body {
+        let aliases = table_aliases_in_select(select);
+
+        let mut select_columns: Vec<ColumnReference> = select.group_by.iter().flat_map(column_references).collect();
+        if let Some(having) = &select.having {
+            select_columns.extend(column_references(having));
+        }
+
+        columns.extend(select_columns.into_iter().map(|column| resolve_column_table(column, &aliases)));
+    }
+
+    // A qualifier that resolves to one of this query's CTEs names no real table either.
+    let cte_names: Vec<String> = query.ctes.iter().map(|cte| cte.alias.name.clone()).collect();
+    columns.into_iter().map(|mut column| {
+        if column.table.as_ref().map_or(false, |table| cte_names.contains(table)) {
+            column.table = None;
+        }
+        column
+    }).collect()
+}
+
+// A bare `ColumnReference.table` is whatever qualifier appears in the SQL, which for
+// `FROM table_1 a` is the alias "a", not the real table name. Resolve it through the
+// query's alias map before anyone uses it to look up information_schema, and drop the
+// qualifier entirely if it doesn't resolve to a real table (e.g. a derived table alias)
+// so validation is skipped rather than run against a name that was never a table.
+fn table_aliases_in_select(select: &Select) -> Vec<(String, String)> {
+    select.from.iter().flat_map(table_aliases_in_table_with_joins).collect()
+}
+
+fn table_aliases_in_table_with_joins(table_with_joins: &TableWithJoins) -> Vec<(String, String)> {
+    let mut aliases = table_aliases_in_table_factor(&table_with_joins.relation);
+    for join in &table_with_joins.joins {
+        aliases.extend(table_aliases_in_table_factor(&join.relation));
+    }
+    aliases
+}
+
+fn table_aliases_in_table_factor(table_factor: &TableFactor) -> Vec<(String, String)> {
+    match table_factor {
+        TableFactor::Table { name, alias, .. } => {
+            let real_table = name.to_string();
+            let key = alias.as_ref().map(|a| a.name.clone()).unwrap_or_else(|| real_table.clone());
+            vec![(key, real_table)]
+        }
+        TableFactor::Derived { .. } => vec![],
+        TableFactor::NestedJoin(table_with_joins) => table_aliases_in_table_with_joins(table_with_joins),
+    }
+}
+
+fn resolve_column_table(column: ColumnReference, aliases: &[(String, String)]) -> ColumnReference {
+    let table = column.table.and_then(|qualifier| {
+        aliases.iter().find(|(alias, _)| *alias == qualifier).map(|(_, real_table)| real_table.clone())
+    });
+    ColumnReference { table, ..column }
+}
+
+fn column_references(expr: &Expr) -> Vec<ColumnReference> {
+    match expr {
+        Expr::Identifier(name) => vec![ColumnReference { table: None, column: name.clone() }],
+        Expr::CompoundIdentifier(parts) if parts.len() == 2 => vec![ColumnReference { table: Some(parts[0].clone()), column: parts[1].clone() }],
+        Expr::BinaryOp { left, right, .. } => {
+            let mut columns = column_references(left);
+            columns.extend(column_references(right));
+            columns
+        }
+        Expr::UnaryOp { expr, .. } => column_references(expr),
+        Expr::Nested(inner) => column_references(inner),
+        Expr::Function(function) => function.args.iter().flat_map(column_references).collect(),
+        _ => vec![],
+    }
+}
+
+fn explain_wrap(sql: &str) -> String {
+    format!("EXPLAIN (ANALYZE, BUFFERS) {}", sql)
+}
+
 struct ClauseStepsBuilder {
     ctes: Vec<Cte>,
     steps: Vec<String>,
@@ -154,17 +514,44 @@ fn get_all_clause_steps(query: &Query) -> Vec<String> {
 }
 
 fn clause_steps_for_query(query: &Query) -> Vec<String> {
-    let ctes = &query.ctes;
-    let mut steps = vec![];
-    if let SetExpr::Select(select) = &query.body {
-        let mut builder_select = create_empty_count_star_select();
+    clause_steps_for_set_expr(&query.body, &query.ctes)
+}
 
-        steps.extend(add_from_and_joins(&mut builder_select, select, ctes));
-        steps.extend(add_selection(&mut builder_select, select, ctes));
-        steps.extend(add_group_bys(&mut builder_select, select, ctes));
-        steps.extend(add_having(&mut builder_select, select, ctes));
+fn clause_steps_for_set_expr(set_expr: &SetExpr, ctes: &[Cte]) -> Vec<String> {
+    match set_expr {
+        SetExpr::Select(select) => {
+            let mut builder_select = create_empty_count_star_select();
+            let mut steps = vec![];
+
+            steps.extend(add_from_and_joins(&mut builder_select, select, ctes));
+            steps.extend(add_selection(&mut builder_select, select, ctes));
+            steps.extend(add_group_bys(&mut builder_select, select, ctes));
+            steps.extend(add_having(&mut builder_select, select, ctes));
+            steps
+        }
+        SetExpr::SetOperation { op, all, left, right } => {
+            let mut steps = clause_steps_for_set_expr(left, ctes);
+            steps.extend(clause_steps_for_set_expr(right, ctes));
+            steps.extend(combined_set_operation_steps(op.clone(), *all, left, right, ctes));
+            steps
+        }
+        _ => vec![],
+    }
+}
+
+fn combined_set_operation_steps(op: SetOperator, all: bool, left: &SetExpr, right: &SetExpr, ctes: &[Cte]) -> Vec<String> {
+    if op == SetOperator::Union && !all {
+        let pre_dedup = SetExpr::SetOperation { op: op.clone(), all: true, left: Box::new(left.clone()), right: Box::new(right.clone()) };
+        let post_dedup = SetExpr::SetOperation { op, all: false, left: Box::new(left.clone()), right: Box::new(right.clone()) };
+
+        vec![
+            format!("{} -- pre-dedup UNION ALL row count", count_wrapped_set_expr(&pre_dedup, ctes)),
+            format!("{} -- post-dedup UNION row count", count_wrapped_set_expr(&post_dedup, ctes)),
+        ]
+    } else {
+        let combined = SetExpr::SetOperation { op, all, left: Box::new(left.clone()), right: Box::new(right.clone()) };
+        vec![count_wrapped_set_expr(&combined, ctes)]
     }
-    steps
 }
 
 fn create_empty_count_star_select() -> Select {
@@ -205,11 +592,128 @@ fn add_from_and_joins(builder_select: &mut Select, source_select: &Select, ctes:
 }
 
 fn add_selection(builder_select: &mut Select, source_select: &Select, ctes: &[Cte]) -> Vec<String> {
-    if let Some(selection) = &source_select.selection {
-        builder_select.selection = Some(selection.clone());
-        vec![query_string_for(builder_select, ctes)]
-    } else {
-        vec![]
+    let selection = match &source_select.selection {
+        Some(selection) => selection.clone(),
+        None => return vec![],
+    };
+
+    let mut steps = vec![];
+
+    // Only a subquery that is itself a top-level AND'd conjunct can be isolated this way:
+    // its count delta is only meaningful if the predicate was actually applied on its own,
+    // which `add_selection` only does for conjuncts. A subquery buried inside an OR (or any
+    // other non-AND combinator) is never applied in isolation, so detecting it here would
+    // emit a row-count step that looks like an isolated EXISTS/IN effect but isn't one.
+    let conjuncts = flatten_conjuncts(&selection);
+    let subqueries: Vec<SubqueryPredicate> = conjuncts.iter().filter_map(subquery_predicate_for).collect();
+
+    for subquery in &subqueries {
+        if let Some(inner_query) = &subquery.inner_query {
+            steps.push(subquery_row_count_step(&source_select.from, inner_query, subquery.negated));
+        }
+    }
+
+    // Apply the non-subquery conjuncts first and the subquery predicates last, regardless
+    // of where they fall in the original WHERE clause, so the count delta introduced by
+    // each subquery step isolates exactly what that EXISTS/IN predicate eliminated.
+    let (subquery_conjuncts, non_subquery_conjuncts): (Vec<Expr>, Vec<Expr>) = conjuncts
+        .into_iter()
+        .partition(|conjunct| subqueries.iter().any(|subquery| &subquery.expr == conjunct));
+
+    let mut accumulated: Option<Expr> = None;
+    for conjunct in non_subquery_conjuncts.into_iter().chain(subquery_conjuncts.into_iter()) {
+        accumulated = Some(match accumulated {
+            Some(previous) => Expr::BinaryOp { left: Box::new(previous), op: BinaryOperator::And, right: Box::new(conjunct.clone()) },
+            None => conjunct.clone(),
+        });
+
+        builder_select.selection = accumulated.clone();
+        let step = query_string_for(builder_select, ctes);
+        steps.push(label_conjunct_step(step, &conjunct, &subqueries));
+    }
+
+    steps
+}
+
+// Flattens the top-level AND tree into its conjuncts so each can be applied (and
+// counted) one at a time. A predicate with no top-level AND is its own single conjunct.
+fn flatten_conjuncts(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            let mut conjuncts = flatten_conjuncts(left);
+            conjuncts.extend(flatten_conjuncts(right));
+            conjuncts
+        }
+        _ => vec![expr.clone()],
+    }
+}
+
+struct SubqueryPredicate {
+    expr: Expr,
+    inner_query: Option<Query>,
+    negated: bool,
+}
+
+// Recognizes `expr` itself as an EXISTS/IN-subquery/IN-list predicate, accounting for an
+// optional outer `NOT` with any parenthesization in between (e.g. `NOT (x IN (...))`).
+// The returned `expr` is always the node passed in, so it matches the conjunct produced
+// by `flatten_conjuncts` for the same position in the tree.
+fn subquery_predicate_for(expr: &Expr) -> Option<SubqueryPredicate> {
+    match expr {
+        Expr::UnaryOp { op: UnaryOperator::Not, expr: inner } => {
+            subquery_predicate_for(inner).map(|subquery| SubqueryPredicate { expr: expr.clone(), negated: !subquery.negated, ..subquery })
+        }
+        Expr::Exists(query) => Some(SubqueryPredicate { expr: expr.clone(), inner_query: Some((**query).clone()), negated: false }),
+        Expr::InSubquery { subquery, negated, .. } => Some(SubqueryPredicate { expr: expr.clone(), inner_query: Some((**subquery).clone()), negated: *negated }),
+        Expr::InList { negated, .. } => Some(SubqueryPredicate { expr: expr.clone(), inner_query: None, negated: *negated }),
+        Expr::Nested(inner) => subquery_predicate_for(inner).map(|subquery| SubqueryPredicate { expr: expr.clone(), ..subquery }),
+        _ => None,
+    }
+}
+
+fn subquery_row_count_step(outer_from: &[TableWithJoins], inner_query: &Query, negated: bool) -> String {
+    let count_sql = correlated_subquery_count_query(outer_from, inner_query);
+    let label = if negated { "anti-join subquery" } else { "semi-join subquery" };
+    format!("{} -- {} row count", count_sql, label)
+}
+
+// A subquery in a WHERE clause (EXISTS/IN) typically correlates against the outer
+// query's tables (e.g. `b.x = a.x`), so counting the subquery body in isolation is
+// invalid SQL -- the outer relation(s) have to be brought into scope alongside it.
+fn correlated_subquery_count_query(outer_from: &[TableWithJoins], inner_query: &Query) -> String {
+    match &inner_query.body {
+        SetExpr::Select(select) => {
+            let mut combined_from = outer_from.to_vec();
+            combined_from.extend(select.from.clone());
+
+            let combined_select = Select {
+                projection: create_count_star_projection(),
+                from: combined_from,
+                selection: select.selection.clone(),
+                group_by: vec![],
+                having: None,
+                distinct: false,
+            };
+
+            query_string_for(&combined_select, &inner_query.ctes)
+        }
+        _ => get_payload_count_query(inner_query),
+    }
+}
+
+fn label_conjunct_step(step: String, conjunct: &Expr, subqueries: &[SubqueryPredicate]) -> String {
+    match subqueries.iter().find(|subquery| &subquery.expr == conjunct) {
+        Some(subquery) => format!("{} -- {}", step, subquery_label(subquery)),
+        None => step,
+    }
+}
+
+fn subquery_label(subquery: &SubqueryPredicate) -> &'static str {
+    match (subquery.negated, subquery.inner_query.is_some()) {
+        (true, true) => "anti-join",
+        (false, true) => "semi-join",
+        (true, false) => "anti-join (NOT IN)",
+        (false, false) => "semi-join (IN)",
     }
 }
 
@@ -257,6 +761,18 @@ mod tests {
         analysis.query_analyses.iter().flat_map(|qa| qa.clause_steps.clone()).collect()
     }
 
+    fn get_explain_steps(analysis: &Analysis) -> Vec<String> {
+        analysis.query_analyses.iter().flat_map(|qa| qa.explain_steps.clone()).collect()
+    }
+
+    fn get_referenced_tables(analysis: &Analysis) -> Vec<String> {
+        analysis.query_analyses.iter().flat_map(|qa| qa.referenced_tables.clone()).collect()
+    }
+
+    fn get_referenced_columns(analysis: &Analysis) -> Vec<ColumnReference> {
+        analysis.query_analyses.iter().flat_map(|qa| qa.referenced_columns.clone()).collect()
+    }
+
     #[test]
     fn creates_one_query_analysis_for_simple_query() {
         let sql = "SELECT * FROM table_1";
@@ -306,6 +822,21 @@ mod tests {
         assert_eq!(expected_clause_steps, clause_steps);
     }
 
+    #[test]
+    fn wraps_each_clause_step_in_an_explain_analyze_buffers_step() {
+        let sql = "SELECT * FROM table_1 JOIN table_2 ON true";
+
+        let expected_explain_steps = vec![
+            "EXPLAIN (ANALYZE, BUFFERS) SELECT COUNT(*) FROM table_1",
+            "EXPLAIN (ANALYZE, BUFFERS) SELECT COUNT(*) FROM table_1 JOIN table_2 ON true",
+        ];
+
+        let analysis = analyze(&sql);
+        let explain_steps = get_explain_steps(&analysis);
+
+        assert_eq!(expected_explain_steps, explain_steps);
+    }
+
     #[test]
     fn decomposes_from_with_multiple_explicitly_joined_tables_to_counting_clause_steps() {
         let sql = "SELECT * FROM table_1 JOIN table_2 ON true LEFT JOIN table_3 ON table_3.x = table_2.x";
@@ -458,15 +989,258 @@ mod tests {
         assert_eq!(expected_payload_count_queries, payload_count_queries)
     }
 
+    #[test]
+    fn drop_ratio_is_zero_when_count_is_unchanged() {
+        assert_eq!(0.0, drop_ratio(10, 10));
+    }
+
+    #[test]
+    fn drop_ratio_is_one_when_count_collapses_to_zero() {
+        assert_eq!(1.0, drop_ratio(10, 0));
+    }
+
     #[test]
     fn checks_the_count_of_the_payload_of_an_insert_statement_using_values() {
         let sql = "INSERT INTO table_1 (a) VALUES (1), (2)";
 
-        let expected_payload_count_queries = vec!["SELECT 2"];
+        let expected_payload_count_queries = vec!["SELECT 2::bigint"];
 
         let analysis = analyze(&sql);
         let payload_count_queries: Vec<String> = analysis.insert_analyses.iter().map(|ia| ia.payload_count.to_string()).collect();
 
         assert_eq!(expected_payload_count_queries, payload_count_queries)
     }
+
+    #[test]
+    fn decomposes_anded_where_predicates_into_one_counting_step_per_conjunct() {
+        let sql = "SELECT * FROM table_1 WHERE a = 1 AND b > 2";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1",
+            "SELECT COUNT(*) FROM table_1 WHERE a = 1",
+            "SELECT COUNT(*) FROM table_1 WHERE a = 1 AND b > 2",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_three_anded_where_predicates_into_three_counting_steps() {
+        let sql = "SELECT * FROM table_1 WHERE a = 1 AND b > 2 AND c < 3";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1",
+            "SELECT COUNT(*) FROM table_1 WHERE a = 1",
+            "SELECT COUNT(*) FROM table_1 WHERE a = 1 AND b > 2",
+            "SELECT COUNT(*) FROM table_1 WHERE a = 1 AND b > 2 AND c < 3",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_not_exists_into_a_subquery_count_and_an_anti_join_step() {
+        let sql = "SELECT * FROM table_1 a WHERE NOT EXISTS (SELECT 1 FROM table_2 b WHERE b.x = a.x)";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1 AS a",
+            "SELECT COUNT(*) FROM table_1 AS a, table_2 AS b WHERE b.x = a.x -- anti-join subquery row count",
+            "SELECT COUNT(*) FROM table_1 AS a WHERE NOT EXISTS (SELECT 1 FROM table_2 AS b WHERE b.x = a.x) -- anti-join",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_in_subquery_into_a_subquery_count_and_a_semi_join_step() {
+        let sql = "SELECT * FROM table_1 a WHERE a.x IN (SELECT b.x FROM table_2 b)";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1 AS a",
+            "SELECT COUNT(*) FROM table_1 AS a, table_2 AS b -- semi-join subquery row count",
+            "SELECT COUNT(*) FROM table_1 AS a WHERE a.x IN (SELECT b.x FROM table_2 AS b) -- semi-join",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn applies_non_subquery_conjuncts_before_the_subquery_predicate_regardless_of_source_order() {
+        let sql = "SELECT * FROM table_1 a WHERE EXISTS (SELECT 1 FROM table_2 b WHERE b.x = a.x) AND a.y = 1";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1 AS a",
+            "SELECT COUNT(*) FROM table_1 AS a, table_2 AS b WHERE b.x = a.x -- semi-join subquery row count",
+            "SELECT COUNT(*) FROM table_1 AS a WHERE a.y = 1",
+            "SELECT COUNT(*) FROM table_1 AS a WHERE a.y = 1 AND EXISTS (SELECT 1 FROM table_2 AS b WHERE b.x = a.x) -- semi-join",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn does_not_isolate_a_subquery_ord_with_another_predicate() {
+        let sql = "SELECT * FROM table_1 a WHERE a.y = 1 OR EXISTS (SELECT 1 FROM table_2 b WHERE b.x = a.x)";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1 AS a",
+            "SELECT COUNT(*) FROM table_1 AS a WHERE a.y = 1 OR EXISTS (SELECT 1 FROM table_2 AS b WHERE b.x = a.x)",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_a_parenthesized_not_in_subquery_into_an_anti_join_step() {
+        let sql = "SELECT * FROM table_1 a WHERE NOT (a.x IN (SELECT b.x FROM table_2 b))";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1 AS a",
+            "SELECT COUNT(*) FROM table_1 AS a, table_2 AS b -- anti-join subquery row count",
+            "SELECT COUNT(*) FROM table_1 AS a WHERE NOT (a.x IN (SELECT b.x FROM table_2 AS b)) -- anti-join",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_union_into_arm_steps_plus_a_pre_and_post_dedup_combined_step() {
+        let sql = "SELECT * FROM table_1 UNION SELECT * FROM table_2";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1",
+            "SELECT COUNT(*) FROM table_2",
+            "SELECT COUNT(*) FROM (SELECT * FROM table_1 UNION ALL SELECT * FROM table_2) AS combined -- pre-dedup UNION ALL row count",
+            "SELECT COUNT(*) FROM (SELECT * FROM table_1 UNION SELECT * FROM table_2) AS combined -- post-dedup UNION row count",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_union_all_into_arm_steps_plus_a_single_combined_step() {
+        let sql = "SELECT * FROM table_1 UNION ALL SELECT * FROM table_2";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1",
+            "SELECT COUNT(*) FROM table_2",
+            "SELECT COUNT(*) FROM (SELECT * FROM table_1 UNION ALL SELECT * FROM table_2) AS combined",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn decomposes_except_into_arm_steps_plus_a_combined_step() {
+        let sql = "SELECT * FROM table_1 EXCEPT SELECT * FROM table_2";
+
+        let expected_clause_steps = vec![
+            "SELECT COUNT(*) FROM table_1",
+            "SELECT COUNT(*) FROM table_2",
+            "SELECT COUNT(*) FROM (SELECT * FROM table_1 EXCEPT SELECT * FROM table_2) AS combined",
+        ];
+
+        let analysis = analyze(&sql);
+        let clause_steps = get_clause_steps(&analysis);
+
+        assert_eq!(expected_clause_steps, clause_steps);
+    }
+
+    #[test]
+    fn checks_the_count_of_the_payload_of_an_insert_statement_using_a_union() {
+        let sql = "INSERT INTO table_1 SELECT * FROM table_2 UNION SELECT * FROM table_3";
+
+        let expected_payload_count_queries = vec!["SELECT COUNT(*) FROM (SELECT * FROM table_2 UNION SELECT * FROM table_3) AS combined"];
+
+        let analysis = analyze(&sql);
+        let payload_count_queries: Vec<String> = analysis.insert_analyses.iter().map(|ia| ia.payload_count.to_string()).collect();
+
+        assert_eq!(expected_payload_count_queries, payload_count_queries)
+    }
+
+    #[test]
+    fn collects_every_table_referenced_by_a_query_including_joins_and_ctes() {
+        let sql = "WITH a AS (SELECT * FROM table_1 JOIN table_2 ON true) SELECT * FROM a JOIN table_3 ON true";
+
+        let expected_tables = vec!["table_1", "table_2", "table_3"];
+
+        let analysis = analyze(&sql);
+        let tables = get_referenced_tables(&analysis);
+
+        assert_eq!(expected_tables, tables);
+    }
+
+    #[test]
+    fn collects_every_table_referenced_by_an_insert_statement() {
+        let sql = "INSERT INTO table_1 SELECT * FROM table_2";
+
+        let expected_tables = vec!["table_1", "table_2"];
+
+        let analysis = analyze(&sql);
+        let tables: Vec<String> = analysis.insert_analyses.iter().flat_map(|ia| ia.referenced_tables.clone()).collect();
+
+        assert_eq!(expected_tables, tables);
+    }
+
+    #[test]
+    fn resolves_qualified_columns_referenced_in_group_by_and_having_through_the_table_alias() {
+        let sql = "SELECT * FROM table_1 a GROUP BY a.x HAVING SUM(a.y) > 1";
+
+        let expected_columns = vec![
+            ColumnReference { table: Some(String::from("table_1")), column: String::from("x") },
+            ColumnReference { table: Some(String::from("table_1")), column: String::from("y") },
+        ];
+
+        let analysis = analyze(&sql);
+        let columns = get_referenced_columns(&analysis);
+
+        assert_eq!(expected_columns, columns);
+    }
+
+    #[test]
+    fn drops_the_table_qualifier_for_a_column_reference_that_does_not_resolve_to_a_real_table() {
+        let sql = "SELECT * FROM (SELECT * FROM table_1) d GROUP BY d.x";
+
+        let expected_columns = vec![
+            ColumnReference { table: None, column: String::from("x") },
+        ];
+
+        let analysis = analyze(&sql);
+        let columns = get_referenced_columns(&analysis);
+
+        assert_eq!(expected_columns, columns);
+    }
+
+    #[test]
+    fn splits_a_schema_qualified_table_name_into_its_schema_and_bare_table_name() {
+        assert_eq!((Some("public"), "table_1"), split_qualified_name("public.table_1"));
+        assert_eq!((None, "table_1"), split_qualified_name("table_1"));
+    }
 }
@@ -5,5 +5,8 @@ use picaresql::Config;
 fn main() {
     let config = Config::from_args();
 
-    picaresql::run(config);
+    if let Err(e) = picaresql::run(config) {
+        eprintln!("picaresql: {}", e);
+        std::process::exit(1);
+    }
 }